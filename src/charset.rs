@@ -0,0 +1,34 @@
+use std::error::Error;
+
+// Looks up a charset by its WHATWG encoding label (e.g. "windows-1252",
+// "shift_jis", "iso-8859-1"), for `--charset`.
+pub struct Charset(&'static encoding_rs::Encoding);
+
+pub fn lookup(label: &str) -> Result<Charset, Box<Error + Send + Sync>> {
+    encoding_rs::Encoding::for_label(label.as_bytes())
+        .map(Charset)
+        .ok_or_else(|| format!("unknown charset: {}", label).into())
+}
+
+impl Charset {
+    // Transcodes a UTF-8 string into this charset's bytes, for encoding.
+    // Characters unmappable in the target charset are replaced with
+    // numeric character references, per the WHATWG encoding spec.
+    pub fn encode(&self, s: &str) -> Vec<u8> {
+        let (bytes, _, _) = self.0.encode(s);
+        bytes.into_owned()
+    }
+
+    // Transcodes bytes in this charset back to UTF-8, for decoding. Under
+    // `strict`, an undecodable byte sequence is a hard error rather than a
+    // lossy U+FFFD substitution.
+    pub fn decode(&self, bytes: &[u8], strict: bool) -> Result<String, Box<Error + Send + Sync>> {
+        let (text, had_errors) = self.0.decode_without_bom_handling(bytes);
+
+        if strict && had_errors {
+            return Err(format!("byte sequence is not valid in charset {}", self.0.name()).into());
+        }
+
+        Ok(text.into_owned())
+    }
+}
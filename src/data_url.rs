@@ -0,0 +1,72 @@
+use std::error::Error;
+use std::io;
+
+use percent_encoding as pe;
+
+use crate::{default_set, percent_encode};
+
+// Parses and serializes RFC 2397 `data:` URLs: `data:[<mediatype>][;base64],<data>`.
+//
+// Unlike the line-oriented modes elsewhere in this crate, the payload is
+// arbitrary bytes (often binary), so it's written to `output` directly
+// rather than through `write_output`, which always appends a trailing '\n'.
+
+pub fn decode<W: io::Write>(input: &str, output: &mut W) -> Result<(), Box<Error + Send + Sync>> {
+    let input = input.trim();
+
+    if !input.starts_with("data:") {
+        return Err("data: URL must start with \"data:\"".into());
+    }
+
+    let rest = &input["data:".len()..];
+    let comma = rest.find(',').ok_or("data: URL is missing its ',' separator")?;
+    let header = &rest[..comma];
+    let body = &rest[comma + 1..];
+
+    let is_base64 = header.ends_with(";base64");
+    let _media_type = if is_base64 {
+        &header[..header.len() - ";base64".len()]
+    } else {
+        header
+    };
+
+    let bytes = if is_base64 {
+        decode_base64(body)?
+    } else {
+        pe::percent_decode(body.as_bytes()).collect::<Vec<u8>>()
+    };
+
+    output.write_all(&bytes)?;
+
+    Ok(())
+}
+
+pub fn encode<W: io::Write>(
+    media_type: &str,
+    use_base64: bool,
+    bytes: &[u8],
+    output: &mut W,
+) -> Result<(), Box<Error + Send + Sync>> {
+    if use_base64 {
+        writeln!(output, "data:{};base64,{}", media_type, base64::encode(bytes))?;
+    } else {
+        writeln!(output, "data:{},{}", media_type, percent_encode(bytes, &default_set()))?;
+    }
+
+    Ok(())
+}
+
+// Tolerates missing padding and ignores ASCII whitespace, both of which
+// are common in hand-written or wrapped `data:` URLs.
+fn decode_base64(body: &str) -> Result<Vec<u8>, Box<Error + Send + Sync>> {
+    let mut cleaned: String = body.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+
+    let remainder = cleaned.len() % 4;
+    if remainder != 0 {
+        for _ in 0..(4 - remainder) {
+            cleaned.push('=');
+        }
+    }
+
+    Ok(base64::decode(&cleaned)?)
+}
@@ -7,8 +7,163 @@ use std::iter;
 use clap::{App, Arg, ArgMatches};
 use percent_encoding as pe;
 
+mod charset;
+mod data_url;
+
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
+// A set of ASCII bytes that should be percent-encoded, represented as a
+// 128-bit bitmap (one bit per ASCII byte). Bytes >= 0x80 are always
+// encoded and aren't part of the bitmap.
+//
+// This replaces the `pe::EncodeSet` trait, which is `Clone + Sized` and so
+// can't be boxed or stored as a single value: picking an encode set used to
+// mean re-matching on `--encode-set` on every line in `transform_line`. A
+// plain value can be resolved once in `run()`,
+// composed with `--encode-chars`/`--keep-chars`, and passed around by
+// reference.
+#[derive(Clone, Copy)]
+pub(crate) struct AsciiSet([u64; 2]);
+
+impl AsciiSet {
+    fn empty() -> AsciiSet {
+        AsciiSet([0, 0])
+    }
+
+    fn contains(&self, byte: u8) -> bool {
+        let AsciiSet(bits) = *self;
+        bits[(byte / 64) as usize] & (1 << (byte % 64)) != 0
+    }
+
+    fn insert(&mut self, byte: u8) {
+        let AsciiSet(ref mut bits) = *self;
+        bits[(byte / 64) as usize] |= 1 << (byte % 64);
+    }
+
+    fn remove(&mut self, byte: u8) {
+        let AsciiSet(ref mut bits) = *self;
+        bits[(byte / 64) as usize] &= !(1 << (byte % 64));
+    }
+
+    fn add_range(&mut self, start: u8, end_inclusive: u8) {
+        for byte in start..=end_inclusive {
+            self.insert(byte);
+        }
+    }
+
+    fn add_chars(&mut self, chars: &str) {
+        for byte in chars.bytes() {
+            if byte < 0x80 {
+                self.insert(byte);
+            }
+        }
+    }
+
+    fn remove_chars(&mut self, chars: &str) {
+        for byte in chars.bytes() {
+            if byte < 0x80 {
+                self.remove(byte);
+            }
+        }
+    }
+
+    fn union(&self, other: &AsciiSet) -> AsciiSet {
+        let AsciiSet(a) = *self;
+        let AsciiSet(b) = *other;
+        AsciiSet([a[0] | b[0], a[1] | b[1]])
+    }
+}
+
+fn chars_set(chars: &str) -> AsciiSet {
+    let mut set = AsciiSet::empty();
+    set.add_chars(chars);
+    set
+}
+
+// The C0 controls and DEL, matching `pe::SIMPLE_ENCODE_SET` (no space).
+fn simple_set() -> AsciiSet {
+    let mut set = AsciiSet::empty();
+    set.add_range(0x00, 0x1F);
+    set.insert(0x7F);
+    set
+}
+
+// `simple_set()` unioned with these, matching `pe::QUERY_ENCODE_SET`.
+fn query_set() -> AsciiSet {
+    simple_set().union(&chars_set(" \"#<>"))
+}
+
+// `query_set()` unioned with these, matching `pe::DEFAULT_ENCODE_SET`.
+pub(crate) fn default_set() -> AsciiSet {
+    query_set().union(&chars_set("`?{}"))
+}
+
+// `default_set()` unioned with these, matching `pe::PATH_SEGMENT_ENCODE_SET`.
+fn path_set() -> AsciiSet {
+    default_set().union(&chars_set("%/"))
+}
+
+// `default_set()` unioned with these, matching `pe::USERINFO_ENCODE_SET`.
+fn userinfo_set() -> AsciiSet {
+    default_set().union(&chars_set("/:;=@[\\]^|"))
+}
+
+// Everything outside the unreserved set (A-Z a-z 0-9 - _ . ~), as used by
+// application/x-www-form-urlencoded. `+` is handled separately, by
+// post-processing an encoded space into it.
+fn form_set() -> AsciiSet {
+    simple_set().union(&chars_set(" !\"#$%&'()*+,/:;<=>?@[\\]^`{|}"))
+}
+
+fn named_set(name: &str) -> AsciiSet {
+    match name {
+        "default" => default_set(),
+        "path" => path_set(),
+        "query" => query_set(),
+        "simple" => simple_set(),
+        "userinfo" => userinfo_set(),
+        _ => panic!("Unknown encode set"),
+    }
+}
+
+// Layers `--encode-chars`/`--keep-chars` onto a base set, so they compose
+// with any named `--encode-set` as well as with `--form`'s implicit set.
+fn layer_chars(mut set: AsciiSet, arg_matches: &ArgMatches) -> AsciiSet {
+    if let Some(chars) = arg_matches.value_of("encode-chars") {
+        set.add_chars(chars);
+    }
+
+    if let Some(chars) = arg_matches.value_of("keep-chars") {
+        set.remove_chars(chars);
+    }
+
+    set
+}
+
+fn resolve_encode_set(arg_matches: &ArgMatches) -> AsciiSet {
+    let base = if arg_matches.is_present("form") {
+        form_set()
+    } else {
+        named_set(arg_matches.value_of("encode-set").unwrap())
+    };
+
+    layer_chars(base, arg_matches)
+}
+
+pub(crate) fn percent_encode(bytes: &[u8], set: &AsciiSet) -> String {
+    let mut encoded = String::with_capacity(bytes.len());
+
+    for &byte in bytes {
+        if byte >= 0x80 || set.contains(byte) {
+            encoded.push_str(&format!("%{:02X}", byte));
+        } else {
+            encoded.push(byte as char);
+        }
+    }
+
+    encoded
+}
+
 fn main() {
     let matches = App::new("urlencode")
         .version(VERSION)
@@ -46,6 +201,86 @@ fn main() {
                      for more details.",
                 ),
         )
+        .arg(
+            Arg::with_name("encode-chars")
+                .long("encode-chars")
+                .takes_value(true)
+                .value_name("CHARS")
+                .help(
+                    "Extra ASCII characters to percent-encode, on top of --encode-set \
+                     (or --form's set).",
+                ),
+        )
+        .arg(
+            Arg::with_name("keep-chars")
+                .long("keep-chars")
+                .takes_value(true)
+                .value_name("CHARS")
+                .help(
+                    "ASCII characters to exclude from percent-encoding, on top of \
+                     --encode-set (or --form's set).",
+                ),
+        )
+        .arg(
+            Arg::with_name("form")
+                .short("f")
+                .long("form")
+                .conflicts_with("encode-set")
+                .help("Treat the input as application/x-www-form-urlencoded key/value pairs.")
+                .long_help(
+                    "Treat the input as application/x-www-form-urlencoded key/value pairs, \
+                     rather than a single value. On decode, each '&'-separated pair is split \
+                     on its first '=', both halves are percent-decoded with '+' mapped to \
+                     space, and the result is printed as 'key<TAB>value' lines. On encode, \
+                     'key=value' or 'key<TAB>value' lines are percent-encoded component-wise \
+                     (encoding everything outside A-Z a-z 0-9 - _ . ~, then turning encoded \
+                     spaces into '+') and joined with '&' into a single output line.",
+                ),
+        )
+        .arg(
+            Arg::with_name("data-url")
+                .long("data-url")
+                .conflicts_with_all(&["encode-set", "encode-chars", "keep-chars", "form"])
+                .help("Parse or produce an RFC 2397 data: URL instead of a bare value.")
+                .long_help(
+                    "Parse or produce an RFC 2397 data: URL (data:[<mediatype>][;base64],<data>) \
+                     instead of a bare value. On decode, the media type and a trailing \
+                     ';base64' are read from before the first comma, and the remainder is \
+                     either base64- or percent-decoded to raw bytes on stdout. On encode, \
+                     stdin is read in full and wrapped as a data: URL, base64-encoded by \
+                     default.",
+                ),
+        )
+        .arg(
+            Arg::with_name("media-type")
+                .long("media-type")
+                .takes_value(true)
+                .default_value("text/plain")
+                .help("The media type to use when producing a data: URL."),
+        )
+        .arg(
+            Arg::with_name("no-base64")
+                .long("no-base64")
+                .requires("data-url")
+                .help("Percent-encode the data: URL body instead of base64-encoding it."),
+        )
+        .arg(
+            Arg::with_name("charset")
+                .long("charset")
+                .takes_value(true)
+                .value_name("NAME")
+                .conflicts_with_all(&["form", "data-url"])
+                .help("Transcode to/from this charset instead of assuming UTF-8.")
+                .long_help(
+                    "Transcode to/from this charset (e.g. windows-1252, shift_jis, \
+                     iso-8859-1) instead of assuming UTF-8. When encoding, the input is \
+                     first transcoded from UTF-8 into the charset and those bytes are \
+                     percent-encoded. When decoding, the percent-decoded bytes are \
+                     interpreted in the charset and transcoded back to UTF-8. Combine with \
+                     --strict-decode to fail on byte sequences that are undecodable in the \
+                     charset, rather than lossily substituting U+FFFD.",
+                ),
+        )
         .arg(
             Arg::with_name("INPUT")
                 .help("The string to encode.")
@@ -65,45 +300,193 @@ fn run(arg_matches: &ArgMatches) -> Result<(), Box<Error + Send + Sync>> {
     let mut stdout_handle = stdout.lock();
     let mut stdin_handle = stdin.lock();
 
+    if arg_matches.is_present("data-url") {
+        return run_data_url(&mut stdin_handle, &mut stdout_handle, arg_matches);
+    }
+
+    let encode_set = resolve_encode_set(arg_matches);
+
+    let charset = match arg_matches.value_of("charset") {
+        Some(label) => Some(charset::lookup(label)?),
+        None => None,
+    };
+
+    if arg_matches.is_present("form") {
+        return run_form(&mut stdin_handle, &mut stdout_handle, arg_matches, &encode_set);
+    }
+
     if arg_matches.is_present("INPUT") {
         let input = arg_matches.value_of("INPUT").unwrap();
-        return transform_line(input, &mut stdout_handle, arg_matches);
+        return transform_line(input, &mut stdout_handle, arg_matches, &encode_set, charset.as_ref());
     }
 
     let mut buf = String::new();
 
     while stdin_handle.read_line(&mut buf)? > 0 {
-        transform_line(buf.trim_end(), &mut stdout_handle, arg_matches)?;
+        transform_line(buf.trim_end(), &mut stdout_handle, arg_matches, &encode_set, charset.as_ref())?;
         buf.clear();
     }
 
     Ok(())
 }
 
+// Drives `--data-url` mode. The payload can be arbitrary binary, so the
+// whole of stdin is read up front rather than line by line.
+fn run_data_url<R: io::Read, W: io::Write>(
+    stdin: &mut R,
+    output: &mut W,
+    arg_matches: &ArgMatches,
+) -> Result<(), Box<Error + Send + Sync>> {
+    let decode_mode = arg_matches.is_present("decode") || arg_matches.is_present("strict-decode");
+
+    if decode_mode {
+        let input = match arg_matches.value_of("INPUT") {
+            Some(input) => input.to_string(),
+            None => {
+                let mut buf = String::new();
+                stdin.read_to_string(&mut buf)?;
+                buf.trim_end().to_string()
+            }
+        };
+
+        return data_url::decode(&input, output);
+    }
+
+    let bytes = match arg_matches.value_of("INPUT") {
+        Some(input) => input.as_bytes().to_vec(),
+        None => {
+            let mut buf = Vec::new();
+            stdin.read_to_end(&mut buf)?;
+            buf
+        }
+    };
+
+    let media_type = arg_matches.value_of("media-type").unwrap();
+    let use_base64 = !arg_matches.is_present("no-base64");
+
+    data_url::encode(media_type, use_base64, &bytes, output)
+}
+
+// Drives `--form` mode. Unlike the plain single-value path, decoding expands
+// one input line into many output lines (one per pair), while encoding
+// collects pairs from every input line and joins them into a single output
+// line, so it can't be expressed through `transform_line`'s one-in-one-out
+// contract.
+fn run_form<R: io::BufRead, W: io::Write>(
+    stdin: &mut R,
+    output: &mut W,
+    arg_matches: &ArgMatches,
+    encode_set: &AsciiSet,
+) -> Result<(), Box<Error + Send + Sync>> {
+    let decode_mode = arg_matches.is_present("decode") || arg_matches.is_present("strict-decode");
+    let lossy = !arg_matches.is_present("strict-decode");
+
+    if decode_mode {
+        if arg_matches.is_present("INPUT") {
+            let input = arg_matches.value_of("INPUT").unwrap();
+            return form_decode_line(input, output, lossy);
+        }
+
+        let mut buf = String::new();
+        while stdin.read_line(&mut buf)? > 0 {
+            form_decode_line(buf.trim_end(), output, lossy)?;
+            buf.clear();
+        }
+
+        return Ok(());
+    }
+
+    let mut pairs = Vec::new();
+
+    if arg_matches.is_present("INPUT") {
+        pairs.push(form_encode_pair(arg_matches.value_of("INPUT").unwrap(), encode_set));
+    } else {
+        let mut buf = String::new();
+        while stdin.read_line(&mut buf)? > 0 {
+            pairs.push(form_encode_pair(buf.trim_end(), encode_set));
+            buf.clear();
+        }
+    }
+
+    let result = write_output(iter::once(pairs.join("&").borrow()), output);
+
+    match result {
+        Err(e) => Err(Box::new(e)),
+        _ => Ok(()),
+    }
+}
+
+// Splits a line on '&' and each pair on its first '=', percent-decoding and
+// '+'-to-space mapping each half, then writes one `key<TAB>value` line per
+// pair to `output`.
+fn form_decode_line<W: io::Write>(line: &str, output: &mut W, lossy: bool) -> Result<(), Box<Error + Send + Sync>> {
+    for pair in line.split('&') {
+        let (key, value) = split_pair(pair);
+        let key = form_decode_component(key, lossy)?;
+        let value = form_decode_component(value, lossy)?;
+
+        write_output(iter::once(format!("{}\t{}", key, value).borrow()), output)
+            .map_err(|e| -> Box<Error + Send + Sync> { Box::new(e) })?;
+    }
+
+    Ok(())
+}
+
+fn split_pair(pair: &str) -> (&str, &str) {
+    match pair.find('=') {
+        Some(idx) => (&pair[..idx], &pair[idx + 1..]),
+        None => (pair, ""),
+    }
+}
+
+fn form_decode_component(component: &str, lossy: bool) -> Result<String, Box<Error + Send + Sync>> {
+    let plus_replaced = component.replace('+', " ");
+    let decoder = pe::percent_decode(plus_replaced.as_bytes());
+
+    let decoded = if lossy {
+        decoder.decode_utf8_lossy().into_owned()
+    } else {
+        decoder.decode_utf8()?.into_owned()
+    };
+
+    Ok(decoded)
+}
+
+// Percent-encodes a single `key=value` (or `key<TAB>value`) line
+// component-wise, per application/x-www-form-urlencoded, and returns it as
+// a single `key=value` string ready to be joined with '&'.
+fn form_encode_pair(line: &str, encode_set: &AsciiSet) -> String {
+    let (key, value) = if let Some(idx) = line.find('\t') {
+        (&line[..idx], &line[idx + 1..])
+    } else {
+        split_pair(line)
+    };
+
+    format!(
+        "{}={}",
+        form_encode_component(key, encode_set),
+        form_encode_component(value, encode_set)
+    )
+}
+
+fn form_encode_component(component: &str, encode_set: &AsciiSet) -> String {
+    percent_encode(component.as_bytes(), encode_set).replace("%20", "+")
+}
+
 fn transform_line<W: io::Write>(
     line: &str,
     output: &mut W,
     arg_matches: &ArgMatches,
+    encode_set: &AsciiSet,
+    charset: Option<&charset::Charset>,
 ) -> Result<(), Box<Error + Send + Sync>> {
     let decode_mode = arg_matches.is_present("decode") || arg_matches.is_present("strict-decode");
     let lossy = !arg_matches.is_present("strict-decode");
 
     if decode_mode {
-        decode(line.as_bytes(), output, lossy)
+        decode(line.as_bytes(), output, lossy, charset)
     } else {
-        // Ugh, unfortunately, since EncodeSet : Cloned : Sized, it
-        // cannot be boxed, so it's impossible to choose our encode set
-        // only once.
-        match arg_matches.value_of("encode-set").unwrap() {
-            "default" => encode(&line, pe::DEFAULT_ENCODE_SET, output)?,
-            "path" => encode(&line, pe::PATH_SEGMENT_ENCODE_SET, output)?,
-            "query" => encode(&line, pe::QUERY_ENCODE_SET, output)?,
-            "simple" => encode(&line, pe::SIMPLE_ENCODE_SET, output)?,
-            "userinfo" => encode(&line, pe::USERINFO_ENCODE_SET, output)?,
-            _ => panic!("Unknown encode set"),
-        };
-
-        Ok(())
+        encode(line, encode_set, output, charset).map_err(|e| -> Box<Error + Send + Sync> { Box::new(e) })
     }
 }
 
@@ -111,13 +494,16 @@ fn decode<W: io::Write>(
     line: &[u8],
     output: &mut W,
     lossy: bool,
+    charset: Option<&charset::Charset>,
 ) -> Result<(), Box<Error + Send + Sync>> {
-    let decoder = pe::percent_decode(line);
+    let percent_decoded: Vec<u8> = pe::percent_decode(line).collect();
 
-    let decoded = if lossy {
-        decoder.decode_utf8_lossy()
+    let decoded = if let Some(charset) = charset {
+        charset.decode(&percent_decoded, !lossy)?
+    } else if lossy {
+        String::from_utf8_lossy(&percent_decoded).into_owned()
     } else {
-        decoder.decode_utf8()?
+        String::from_utf8(percent_decoded)?
     };
 
     let result = write_output(iter::once(decoded.borrow()), output);
@@ -128,13 +514,18 @@ fn decode<W: io::Write>(
     }
 }
 
-fn encode<W: io::Write, E: pe::EncodeSet>(
+fn encode<W: io::Write>(
     line: &str,
-    encode_set: E,
+    encode_set: &AsciiSet,
     output: &mut W,
+    charset: Option<&charset::Charset>,
 ) -> io::Result<()> {
-    let encoded = pe::utf8_percent_encode(line, encode_set);
-    write_output(encoded, output)
+    let bytes = match charset {
+        Some(charset) => charset.encode(line),
+        None => line.as_bytes().to_vec(),
+    };
+
+    write_output(iter::once(percent_encode(&bytes, encode_set).borrow()), output)
 }
 
 fn write_output<'a, B, W>(strings: B, output: &mut W) -> io::Result<()>